@@ -0,0 +1,40 @@
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub(crate) enum ServerError {
+    #[error("function `{0}` has no event source at index {1}")]
+    EventSourceNotFound(String, usize),
+    #[error("no invocation `{0}` is waiting for a response")]
+    RequestNotFound(String),
+    #[error("no response was received from the Lambda runtime")]
+    NoResponse,
+    #[error("failed to poll event source: {0}")]
+    EventSourceIoError(String),
+    #[error("the Lambda runtime reported an error while streaming the response: {0}")]
+    StreamingRuntimeError(String),
+    #[error(transparent)]
+    SpawnError(#[from] watchexec::error::CriticalError),
+    #[error(transparent)]
+    RecvError(#[from] tokio::sync::oneshot::error::RecvError),
+    #[error(transparent)]
+    Axum(#[from] axum::Error),
+    #[error(transparent)]
+    InvalidHeader(#[from] http::header::InvalidHeaderValue),
+}
+
+impl IntoResponse for ServerError {
+    fn into_response(self) -> Response {
+        let status = match self {
+            ServerError::EventSourceNotFound(_, _) | ServerError::RequestNotFound(_) => {
+                StatusCode::NOT_FOUND
+            }
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+
+        (status, self.to_string()).into_response()
+    }
+}
@@ -0,0 +1,389 @@
+use std::{collections::VecDeque, sync::Arc};
+
+use serde_json::{json, Value};
+use tokio::{
+    sync::{mpsc::Sender, RwLock},
+    time::interval,
+};
+use tokio_graceful_shutdown::SubsystemHandle;
+use tracing::{error, info, warn};
+
+use crate::{
+    error::ServerError,
+    requests::{InvokeRequest, InvokeResponse},
+    state::SourceQueueRegistry,
+    watcher::{EventSourceConfig, EventSourceKind},
+};
+
+/// An in-memory stand-in for the SQS queue / SNS topic / Kinesis or
+/// DynamoDB stream an event source mapping would otherwise read from.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct SourceQueue {
+    inner: Arc<RwLock<VecDeque<Value>>>,
+}
+
+impl SourceQueue {
+    /// Enqueues a record for the next poll to pick up.
+    pub(crate) async fn push(&self, record: Value) {
+        self.inner.write().await.push_back(record);
+    }
+
+    /// Pops up to `max` records off the front of the queue, making them
+    /// unavailable to a subsequent poll until [`requeue_front`] puts them
+    /// back.
+    async fn drain(&self, max: usize) -> Vec<Value> {
+        let mut inner = self.inner.write().await;
+        let count = inner.len().min(max);
+        inner.drain(..count).collect()
+    }
+
+    /// Puts `records` back at the front of the queue, in their original
+    /// order, so a failed batch is retried before anything enqueued since.
+    async fn requeue_front(&self, records: Vec<Value>) {
+        let mut inner = self.inner.write().await;
+        for record in records.into_iter().rev() {
+            inner.push_front(record);
+        }
+    }
+}
+
+/// Spawns one poller task per event source configured for `function_name`,
+/// submitting the batches each one assembles through `req_tx` — the same
+/// channel `cargo lambda watch` uses for synchronous HTTP invokes — so a
+/// function can't tell a local batch from a real event source mapping.
+///
+/// Each source's [`SourceQueue`] is also registered with `registry` under
+/// `(function_name, index)`, so `POST /event-sources/{function_name}/{index}`
+/// (see `runtime_api::push_event_source_record`) can feed it records from
+/// outside the process.
+pub(crate) fn spawn_pollers(
+    subsys: &SubsystemHandle,
+    function_name: &str,
+    sources: Vec<EventSourceConfig>,
+    req_tx: Sender<InvokeRequest>,
+    registry: SourceQueueRegistry,
+) {
+    for (index, source) in sources.into_iter().enumerate() {
+        let function_name = function_name.to_owned();
+        let req_tx = req_tx.clone();
+        let registry = registry.clone();
+        let queue = source.queue.clone();
+        subsys.start(
+            &format!("event source poller {index} for {function_name}"),
+            move |s| async move {
+                registry.register(&function_name, index, queue).await;
+                poll_source(s, function_name, source, req_tx).await
+            },
+        );
+    }
+}
+
+async fn poll_source(
+    subsys: SubsystemHandle,
+    function_name: String,
+    source: EventSourceConfig,
+    req_tx: Sender<InvokeRequest>,
+) -> Result<(), ServerError> {
+    let mut tick = interval(source.poll_interval);
+    let mut backoff = source.visibility_timeout;
+    let mut attempts: usize = 0;
+
+    loop {
+        tokio::select! {
+            _ = tick.tick() => {
+                let (records, commit) = match next_batch(&source).await {
+                    Ok((records, commit)) if records.is_empty() => {
+                        // Nothing to invoke, but a `File` source may still
+                        // have consumed a run of blank lines — advance past
+                        // them now so the poller doesn't reread them forever.
+                        commit.apply().await;
+                        continue;
+                    }
+                    Ok(batch) => batch,
+                    Err(err) => {
+                        error!(error = %err, function = ?function_name, "failed to poll event source");
+                        continue;
+                    }
+                };
+
+                let payload = envelope(&source.kind, &records);
+                let (resp_tx, resp_rx) = tokio::sync::oneshot::channel();
+                let req = InvokeRequest {
+                    req_id: uuid::Uuid::new_v4().to_string(),
+                    function_name: function_name.clone(),
+                    payload,
+                    resp_tx,
+                };
+
+                if req_tx.send(req).await.is_err() {
+                    info!(function = ?function_name, "scheduler gone, stopping event source poller");
+                    return Ok(());
+                }
+
+                match resp_rx.await {
+                    Ok(InvokeResponse::Error(_)) | Err(_) => {
+                        attempts += 1;
+                        let exhausted =
+                            source.dead_letter.is_some() && attempts >= source.max_receive_count;
+
+                        warn!(
+                            function = ?function_name,
+                            backoff = ?backoff,
+                            attempt = attempts,
+                            "batch failed, retrying after visibility timeout"
+                        );
+
+                        if exhausted {
+                            let dead_letter = source
+                                .dead_letter
+                                .as_ref()
+                                .expect("exhausted implies dead_letter is configured");
+                            commit.apply().await;
+                            let _ = dead_letter.send(records).await;
+                            attempts = 0;
+                        } else if !matches!(source.kind, EventSourceKind::File { .. }) {
+                            // Queue-backed sources: put the batch back so
+                            // it's redelivered once the backoff elapses,
+                            // matching a visibility timeout expiring.
+                            source.queue.requeue_front(records).await;
+                        }
+                        // File sources, not yet exhausted: the line cursor
+                        // is only advanced by `commit`, which isn't applied
+                        // here, so the next poll re-reads the same lines
+                        // once the backoff elapses, matching a visibility
+                        // timeout expiring.
+
+                        tokio::select! {
+                            _ = tokio::time::sleep(backoff) => {}
+                            _ = subsys.on_shutdown_requested() => {
+                                info!(function = ?function_name, "terminating event source poller");
+                                return Ok(());
+                            }
+                        }
+                        backoff = (backoff * 2).min(source.max_backoff);
+                    }
+                    Ok(_) => {
+                        commit.apply().await;
+                        backoff = source.visibility_timeout;
+                        attempts = 0;
+                    }
+                }
+            },
+            _ = subsys.on_shutdown_requested() => {
+                info!(function = ?function_name, "terminating event source poller");
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// What's left to do once a batch [`next_batch`] returned is actually
+/// delivered. Queue-backed sources already removed their records from
+/// `source.queue` by the time `next_batch` returns, so there's nothing
+/// left to commit; a failed batch is put back with `requeue_front`
+/// instead. A `File` source's cursor, however, must not advance until the
+/// batch is known to have succeeded, or a failed batch would be lost —
+/// its commit is deferred here and only applied once the invocation
+/// actually succeeds.
+enum BatchCommit {
+    None,
+    File {
+        cursor: Arc<RwLock<usize>>,
+        consumed: usize,
+    },
+}
+
+impl BatchCommit {
+    async fn apply(self) {
+        if let BatchCommit::File { cursor, consumed } = self {
+            *cursor.write().await += consumed;
+        }
+    }
+}
+
+/// Pulls up to `batch_size` records from the source without assembling
+/// them into an event envelope yet, alongside the [`BatchCommit`] that
+/// must be applied once those records are confirmed delivered.
+async fn next_batch(source: &EventSourceConfig) -> Result<(Vec<Value>, BatchCommit), ServerError> {
+    match &source.kind {
+        EventSourceKind::File { path, cursor } => {
+            // `read_to_string` blocks on disk I/O, so it's run via
+            // `block_in_place` rather than directly in this poller's async
+            // task, the same way `watcher::new`'s preflight `cargo build`
+            // is — otherwise a large or slow-disk source would stall this
+            // worker thread's other async work, including unrelated
+            // functions' invocation handling, on every poll tick.
+            let contents = tokio::task::block_in_place(|| {
+                std::fs::read_to_string(path).map_err(|err| {
+                    ServerError::EventSourceIoError(format!("{}: {err}", path.display()))
+                })
+            })?;
+            let lines: Vec<&str> = contents.lines().collect();
+
+            let start = *cursor.read().await;
+            if start >= lines.len() {
+                return Ok((Vec::new(), BatchCommit::None));
+            }
+
+            let mut records = Vec::new();
+            let mut consumed = 0;
+            for line in &lines[start..] {
+                consumed += 1;
+                if !line.trim().is_empty() {
+                    records.push(serde_json::from_str(line).unwrap_or(Value::Null));
+                    if records.len() == source.batch_size {
+                        break;
+                    }
+                }
+            }
+
+            Ok((
+                records,
+                BatchCommit::File {
+                    cursor: cursor.clone(),
+                    consumed,
+                },
+            ))
+        }
+        // SQS, SNS, Kinesis, and DynamoDB streams are backed by
+        // `source.queue`, a local stand-in for the real queue/topic/stream
+        // that a test harness pushes records onto; polling them is a
+        // matter of draining what's been buffered since the last tick.
+        // `drain` already removes them, so there's nothing left to commit.
+        EventSourceKind::Sqs { .. }
+        | EventSourceKind::Sns { .. }
+        | EventSourceKind::Kinesis { .. }
+        | EventSourceKind::DynamoDbStream { .. } => Ok((
+            source.queue.drain(source.batch_size).await,
+            BatchCommit::None,
+        )),
+    }
+}
+
+/// Wraps `records` in the JSON shape AWS uses to invoke a function for
+/// this source (`aws_lambda_events`' SQS/SNS/Kinesis/DynamoDB stream event
+/// types).
+fn envelope(kind: &EventSourceKind, records: &[Value]) -> Value {
+    match kind {
+        EventSourceKind::Sqs { queue_url } => json!({
+            "Records": records.iter().map(|body| json!({
+                "eventSource": "aws:sqs",
+                "eventSourceARN": queue_url,
+                "body": body,
+            })).collect::<Vec<_>>(),
+        }),
+        EventSourceKind::Sns { topic_arn } => json!({
+            "Records": records.iter().map(|message| json!({
+                "EventSource": "aws:sns",
+                "Sns": {
+                    "TopicArn": topic_arn,
+                    "Message": message,
+                },
+            })).collect::<Vec<_>>(),
+        }),
+        EventSourceKind::Kinesis { stream_arn } => json!({
+            "Records": records.iter().map(|data| json!({
+                "eventSource": "aws:kinesis",
+                "eventSourceARN": stream_arn,
+                "kinesis": { "data": data },
+            })).collect::<Vec<_>>(),
+        }),
+        EventSourceKind::DynamoDbStream { stream_arn } => json!({
+            "Records": records.iter().map(|change| json!({
+                "eventSource": "aws:dynamodb",
+                "eventSourceARN": stream_arn,
+                "dynamodb": change,
+            })).collect::<Vec<_>>(),
+        }),
+        EventSourceKind::File { .. } => json!({ "Records": records }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sqs_envelope_wraps_body_with_queue_url() {
+        let kind = EventSourceKind::Sqs {
+            queue_url: "https://sqs.local/queue".into(),
+        };
+        let envelope = envelope(&kind, &[json!({"hello": "world"})]);
+        assert_eq!(
+            envelope,
+            json!({
+                "Records": [{
+                    "eventSource": "aws:sqs",
+                    "eventSourceARN": "https://sqs.local/queue",
+                    "body": {"hello": "world"},
+                }],
+            })
+        );
+    }
+
+    #[test]
+    fn sns_envelope_wraps_message_under_sns_key() {
+        let kind = EventSourceKind::Sns {
+            topic_arn: "arn:aws:sns:us-east-1:000000000000:topic".into(),
+        };
+        let envelope = envelope(&kind, &[json!("a message")]);
+        assert_eq!(
+            envelope,
+            json!({
+                "Records": [{
+                    "EventSource": "aws:sns",
+                    "Sns": {
+                        "TopicArn": "arn:aws:sns:us-east-1:000000000000:topic",
+                        "Message": "a message",
+                    },
+                }],
+            })
+        );
+    }
+
+    #[test]
+    fn kinesis_envelope_wraps_data_under_kinesis_key() {
+        let kind = EventSourceKind::Kinesis {
+            stream_arn: "arn:aws:kinesis:us-east-1:000000000000:stream/test".into(),
+        };
+        let envelope = envelope(&kind, &[json!("some data")]);
+        assert_eq!(
+            envelope,
+            json!({
+                "Records": [{
+                    "eventSource": "aws:kinesis",
+                    "eventSourceARN": "arn:aws:kinesis:us-east-1:000000000000:stream/test",
+                    "kinesis": {"data": "some data"},
+                }],
+            })
+        );
+    }
+
+    #[test]
+    fn dynamodb_envelope_wraps_change_under_dynamodb_key() {
+        let kind = EventSourceKind::DynamoDbStream {
+            stream_arn: "arn:aws:dynamodb:us-east-1:000000000000:table/test/stream/1".into(),
+        };
+        let envelope = envelope(&kind, &[json!({"Keys": {"id": {"S": "1"}}})]);
+        assert_eq!(
+            envelope,
+            json!({
+                "Records": [{
+                    "eventSource": "aws:dynamodb",
+                    "eventSourceARN": "arn:aws:dynamodb:us-east-1:000000000000:table/test/stream/1",
+                    "dynamodb": {"Keys": {"id": {"S": "1"}}},
+                }],
+            })
+        );
+    }
+
+    #[test]
+    fn file_envelope_wraps_records_directly() {
+        let kind = EventSourceKind::File {
+            path: "records.jsonl".into(),
+            cursor: Arc::new(RwLock::new(0)),
+        };
+        let envelope = envelope(&kind, &[json!({"id": 1}), json!({"id": 2})]);
+        assert_eq!(envelope, json!({ "Records": [{"id": 1}, {"id": 2}] }));
+    }
+}
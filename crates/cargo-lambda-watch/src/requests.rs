@@ -0,0 +1,72 @@
+use bytes::Bytes;
+use serde_json::Value;
+use tokio::sync::{mpsc, oneshot};
+
+use crate::error::ServerError;
+
+/// The content type a handler sets on its response to opt into the Lambda
+/// Rust runtime's streaming invoke mode (`lambda_runtime::streaming`'s
+/// `content_type` header).
+pub(crate) const STREAMING_RESPONSE_CONTENT_TYPE: &str =
+    "application/vnd.awslambda.http-integration-response";
+
+/// Trailer the runtime crate sends once a streamed response finishes with
+/// an error, instead of terminating the chunked body cleanly.
+pub(crate) const RUNTIME_FUNCTION_ERROR_TYPE_TRAILER: &str = "Lambda-Runtime-Function-Error-Type";
+
+/// Headers `GET /runtime/invocation/next` sets alongside the event
+/// payload, matching the real Lambda Runtime API's `Context` headers.
+pub(crate) const AWS_REQUEST_ID_HEADER: &str = "Lambda-Runtime-Aws-Request-Id";
+pub(crate) const DEADLINE_MS_HEADER: &str = "Lambda-Runtime-Deadline-Ms";
+pub(crate) const INVOKED_FUNCTION_ARN_HEADER: &str = "Lambda-Runtime-Invoked-Function-Arn";
+pub(crate) const FUNCTION_MEMORY_SIZE_HEADER: &str = "Lambda-Runtime-Function-Memory-Size";
+
+/// A single invocation waiting to be picked up by a function's poll loop.
+pub(crate) struct InvokeRequest {
+    pub(crate) req_id: String,
+    pub(crate) function_name: String,
+    pub(crate) payload: Value,
+    pub(crate) resp_tx: oneshot::Sender<InvokeResponse>,
+}
+
+/// The reply delivered back to the invoking client.
+///
+/// Most handlers reply with [`InvokeResponse::Buffered`]: the runtime API
+/// handler waits for the whole body before replying to `cargo lambda
+/// invoke`. A handler that responds with
+/// [`STREAMING_RESPONSE_CONTENT_TYPE`] and `Transfer-Encoding: chunked`
+/// instead gets [`InvokeResponse::Streaming`], whose chunks are forwarded
+/// to the client as soon as the handler writes them. [`InvokeResponse::Error`]
+/// is used for invocations the emulator itself fails, such as a timeout.
+pub(crate) enum InvokeResponse {
+    Buffered(Vec<u8>),
+    Streaming(mpsc::Receiver<Result<Bytes, ServerError>>),
+    Error(String),
+}
+
+/// The subset of `lambda_runtime::Context` the emulator can fill in
+/// locally, handed to the function alongside its event payload.
+#[derive(Clone, Debug)]
+pub(crate) struct InvokeContext {
+    pub(crate) aws_request_id: String,
+    /// Milliseconds since the Unix epoch by which the handler must return.
+    pub(crate) deadline_ms: u64,
+    pub(crate) memory_limit_in_mb: i32,
+    pub(crate) invoked_function_arn: String,
+}
+
+/// A Lambda Extensions API event fanned out to every extension registered
+/// for a function. Invocation payloads and build errors aren't routed
+/// through here: they're delivered straight to the invoking client via
+/// [`crate::state::RequestCache::queue_invocation`] and
+/// [`crate::state::RequestCache::fail_current`], which actually have a
+/// reader on the other end.
+pub(crate) enum NextEvent {
+    Shutdown(String),
+}
+
+impl NextEvent {
+    pub(crate) fn shutdown(reason: &str) -> Self {
+        NextEvent::Shutdown(reason.into())
+    }
+}
@@ -0,0 +1,234 @@
+use axum::{
+    body::Body,
+    extract::{Json, Path, State},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+};
+use http_body_util::BodyExt;
+use serde_json::Value;
+use tokio::sync::mpsc;
+
+use crate::{
+    error::ServerError,
+    requests::{
+        InvokeResponse, AWS_REQUEST_ID_HEADER, DEADLINE_MS_HEADER, FUNCTION_MEMORY_SIZE_HEADER,
+        INVOKED_FUNCTION_ARN_HEADER, RUNTIME_FUNCTION_ERROR_TYPE_TRAILER,
+        STREAMING_RESPONSE_CONTENT_TYPE,
+    },
+    state::RuntimeState,
+};
+
+/// `POST /runtime/invocation/{req_id}/response`
+///
+/// Delivers a handler's response back to the invoking client. By default
+/// the whole body is buffered before it's handed to the waiting
+/// `cargo lambda invoke` request. When the handler instead replies with
+/// `Content-Type: application/vnd.awslambda.http-integration-response` and
+/// `Transfer-Encoding: chunked` — the Lambda Rust runtime's streaming
+/// invoke mode — each body chunk is forwarded to the client as soon as
+/// it's written, and a `Lambda-Runtime-Function-Error-Type` trailer is
+/// surfaced as a mid-stream error instead of being dropped silently.
+pub(crate) async fn response(
+    Path(req_id): Path<String>,
+    State(state): State<RuntimeState>,
+    headers: HeaderMap,
+    body: Body,
+) -> Result<StatusCode, ServerError> {
+    let resp_tx = state
+        .req_cache
+        .take_response_channel(&req_id)
+        .await
+        .ok_or_else(|| ServerError::RequestNotFound(req_id.clone()))?;
+
+    if is_streaming_response(&headers) {
+        let (tx, rx) = mpsc::channel(16);
+        resp_tx
+            .send(InvokeResponse::Streaming(rx))
+            .map_err(|_| ServerError::NoResponse)?;
+
+        tokio::spawn(forward_streaming_body(body, tx));
+    } else {
+        let bytes = body.collect().await.map_err(ServerError::Axum)?.to_bytes();
+        resp_tx
+            .send(InvokeResponse::Buffered(bytes.to_vec()))
+            .map_err(|_| ServerError::NoResponse)?;
+    }
+
+    Ok(StatusCode::ACCEPTED)
+}
+
+/// Relays each data frame of `body` to `tx` as it arrives, and turns a
+/// trailing `Lambda-Runtime-Function-Error-Type` trailer into a final
+/// error message instead of a clean end-of-stream.
+async fn forward_streaming_body(
+    mut body: Body,
+    tx: mpsc::Sender<Result<bytes::Bytes, ServerError>>,
+) {
+    loop {
+        let frame = match body.frame().await {
+            Some(Ok(frame)) => frame,
+            Some(Err(err)) => {
+                let _ = tx.send(Err(ServerError::Axum(err))).await;
+                return;
+            }
+            None => return,
+        };
+
+        if let Some(chunk) = frame.data_ref() {
+            if tx.send(Ok(chunk.clone())).await.is_err() {
+                return;
+            }
+            continue;
+        }
+
+        if let Some(trailers) = frame.trailers_ref() {
+            if let Some(error_type) = trailers
+                .get(RUNTIME_FUNCTION_ERROR_TYPE_TRAILER)
+                .and_then(|value| value.to_str().ok())
+            {
+                let _ = tx
+                    .send(Err(ServerError::StreamingRuntimeError(
+                        error_type.to_string(),
+                    )))
+                    .await;
+                return;
+            }
+        }
+    }
+}
+
+/// `GET /{function_name}/{instance_id}/runtime/invocation/next`
+///
+/// The actual production/consumption point for an invocation: hands the
+/// instance the oldest invocation [`crate::state::RequestCache::queue_invocation`]
+/// queued for it and it hasn't picked up yet, as a JSON body plus the `Context` headers the
+/// Lambda Rust runtime expects, or [`StatusCode::NO_CONTENT`] if nothing
+/// is queued yet. This is also where the invocation's timeout watcher
+/// actually starts counting down, not when it was queued — a request that
+/// waited behind an already-busy instance only starts risking a timeout
+/// once that instance is actually working on it.
+pub(crate) async fn next_invocation(
+    Path((function_name, instance_id)): Path<(String, usize)>,
+    State(state): State<RuntimeState>,
+) -> Result<Response, ServerError> {
+    let Some((req_id, payload, context, timeout)) = state
+        .req_cache
+        .next_invocation(&function_name, instance_id)
+        .await
+    else {
+        return Ok(StatusCode::NO_CONTENT.into_response());
+    };
+
+    if timeout > std::time::Duration::ZERO {
+        crate::scheduler::spawn_timeout_watcher(
+            state.req_cache.clone(),
+            state.terminate_txs.clone(),
+            function_name,
+            instance_id,
+            req_id.clone(),
+            timeout,
+        );
+    }
+
+    let mut headers = HeaderMap::new();
+    headers.insert(AWS_REQUEST_ID_HEADER, HeaderValue::from_str(&req_id)?);
+    headers.insert(
+        DEADLINE_MS_HEADER,
+        HeaderValue::from_str(&context.deadline_ms.to_string())?,
+    );
+    headers.insert(
+        INVOKED_FUNCTION_ARN_HEADER,
+        HeaderValue::from_str(&context.invoked_function_arn)?,
+    );
+    headers.insert(
+        FUNCTION_MEMORY_SIZE_HEADER,
+        HeaderValue::from_str(&context.memory_limit_in_mb.to_string())?,
+    );
+
+    Ok((headers, Json(payload)).into_response())
+}
+
+/// `POST /event-sources/{function_name}/{index}`
+///
+/// Pushes `record` onto the local [`crate::event_source::SourceQueue`]
+/// backing `function_name`'s event source mapping at `index`, standing in
+/// for writing to the real SQS queue, SNS topic, or Kinesis/DynamoDB
+/// stream.
+pub(crate) async fn push_event_source_record(
+    Path((function_name, index)): Path<(String, usize)>,
+    State(state): State<RuntimeState>,
+    Json(record): Json<Value>,
+) -> Result<StatusCode, ServerError> {
+    let queue = state
+        .source_queues
+        .get(&function_name, index)
+        .await
+        .ok_or_else(|| ServerError::EventSourceNotFound(function_name.clone(), index))?;
+
+    queue.push(record).await;
+
+    Ok(StatusCode::ACCEPTED)
+}
+
+/// Whether a handler's response opted into the streaming invoke mode.
+fn is_streaming_response(headers: &HeaderMap) -> bool {
+    let is_streaming_content_type = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with(STREAMING_RESPONSE_CONTENT_TYPE));
+
+    let is_chunked = headers
+        .get(header::TRANSFER_ENCODING)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.eq_ignore_ascii_case("chunked"));
+
+    is_streaming_content_type && is_chunked
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(content_type: Option<&str>, transfer_encoding: Option<&str>) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        if let Some(content_type) = content_type {
+            headers.insert(header::CONTENT_TYPE, content_type.parse().unwrap());
+        }
+        if let Some(transfer_encoding) = transfer_encoding {
+            headers.insert(
+                header::TRANSFER_ENCODING,
+                transfer_encoding.parse().unwrap(),
+            );
+        }
+        headers
+    }
+
+    #[test]
+    fn streaming_content_type_and_chunked_encoding_is_streaming() {
+        assert!(is_streaming_response(&headers(
+            Some(STREAMING_RESPONSE_CONTENT_TYPE),
+            Some("chunked"),
+        )));
+    }
+
+    #[test]
+    fn streaming_content_type_without_chunked_encoding_is_not_streaming() {
+        assert!(!is_streaming_response(&headers(
+            Some(STREAMING_RESPONSE_CONTENT_TYPE),
+            None,
+        )));
+    }
+
+    #[test]
+    fn chunked_encoding_without_streaming_content_type_is_not_streaming() {
+        assert!(!is_streaming_response(&headers(
+            Some("application/json"),
+            Some("chunked"),
+        )));
+    }
+
+    #[test]
+    fn no_headers_is_not_streaming() {
+        assert!(!is_streaming_response(&headers(None, None)));
+    }
+}
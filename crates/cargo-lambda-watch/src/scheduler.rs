@@ -2,19 +2,28 @@ use std::sync::Arc;
 
 use crate::{
     error::ServerError,
-    requests::{InvokeRequest, NextEvent},
-    state::{ExtensionCache, RuntimeState},
+    event_source,
+    requests::{InvokeContext, InvokeRequest, InvokeResponse, NextEvent},
+    state::{ExtensionCache, RuntimeState, TerminateRegistry},
     watcher::WatcherConfig,
     CargoOptions,
 };
 use cargo_lambda_invoke::DEFAULT_PACKAGE_FUNCTION;
+use std::time::Duration;
 use tokio::sync::{
     mpsc::{self, Receiver, Sender},
     oneshot,
 };
 use tokio_graceful_shutdown::SubsystemHandle;
-use tracing::{error, info};
-use watchexec::{command::Command, Watchexec};
+use tracing::{error, info, warn};
+use watchexec::{
+    command::{Command, Shell},
+    Watchexec,
+};
+
+/// How often to sweep every function's pool for instances that have idled
+/// past their TTL.
+const IDLE_SWEEP_INTERVAL: std::time::Duration = std::time::Duration::from_secs(30);
 
 pub(crate) async fn init_scheduler(
     subsys: &SubsystemHandle,
@@ -24,8 +33,17 @@ pub(crate) async fn init_scheduler(
 ) -> Sender<InvokeRequest> {
     let (req_tx, req_rx) = mpsc::channel::<InvokeRequest>(100);
 
+    let scheduler_tx = req_tx.clone();
     subsys.start("lambda scheduler", move |s| async move {
-        start_scheduler(s, state, cargo_options, watcher_config, req_rx).await
+        start_scheduler(
+            s,
+            state,
+            cargo_options,
+            watcher_config,
+            req_rx,
+            scheduler_tx,
+        )
+        .await
     });
 
     req_tx
@@ -37,39 +55,141 @@ async fn start_scheduler(
     cargo_options: CargoOptions,
     watcher_config: WatcherConfig,
     mut req_rx: Receiver<InvokeRequest>,
+    req_tx: Sender<InvokeRequest>,
 ) -> Result<(), ServerError> {
-    let (gc_tx, mut gc_rx) = mpsc::channel::<String>(10);
+    let (gc_tx, mut gc_rx) = mpsc::channel::<(String, usize)>(10);
     let (wx_tx, mut wx_rx) = mpsc::channel::<(
         tokio::sync::oneshot::Sender<Result<Arc<Watchexec>, ServerError>>,
         Command,
         WatcherConfig,
-        ExtensionCache,
+        CargoOptions,
+        usize,
     )>(10);
 
+    let wx_req_cache = state.req_cache.clone();
     tokio::spawn(async move {
-        while let Some((tx, cmd, watcher_config, ext_cache)) = wx_rx.recv().await {
-            let wx = crate::watcher::new(cmd, watcher_config, ext_cache).await;
+        while let Some((tx, cmd, watcher_config, cargo_options, instance_id)) = wx_rx.recv().await {
+            let wx = crate::watcher::new(
+                cmd,
+                watcher_config,
+                cargo_options,
+                wx_req_cache.clone(),
+                instance_id,
+            )
+            .await;
             let _ = tx.send(wx);
         }
     });
 
+    let terminate_txs = state.terminate_txs.clone();
+
+    // Event sources drive a function on their own schedule, with no
+    // synchronous invoke required to prime them, so the pollers start as
+    // soon as the scheduler does rather than waiting on a first
+    // `InvokeRequest` that may never come.
+    if !watcher_config.event_sources.is_empty() {
+        event_source::spawn_pollers(
+            &subsys,
+            &watcher_config.name,
+            watcher_config.event_sources.clone(),
+            req_tx.clone(),
+            state.source_queues.clone(),
+        );
+    }
+
+    let mut idle_sweep = tokio::time::interval(IDLE_SWEEP_INTERVAL);
+    let idle_req_cache = state.req_cache.clone();
+    let idle_gc_tx = gc_tx.clone();
+    tokio::spawn(async move {
+        loop {
+            idle_sweep.tick().await;
+            for (name, instance_id) in idle_req_cache.idle_instances().await {
+                if idle_gc_tx.send((name, instance_id)).await.is_err() {
+                    return;
+                }
+            }
+        }
+    });
+
     loop {
         tokio::select! {
             Some(req) = req_rx.recv() => {
-                let result = state.req_cache.upsert(req).await?;
-                if let Some((name, api)) = result {
-                    if !watcher_config.only_lambda_apis {
-                        let gc_tx = gc_tx.clone();
-                        let cargo_options = cargo_options.clone();
-                        let watcher_config = watcher_config.clone();
-                        let ext_cache = state.ext_cache.clone();
-                        let wx_tx = wx_tx.clone();
-                        subsys.start("lambda runtime", move |s| start_function(s, name, api, cargo_options, watcher_config, gc_tx, ext_cache, wx_tx));
+                let function_name = req.function_name.clone();
+                let req_id = req.req_id.clone();
+                let payload = req.payload.clone();
+                let dispatch = state.req_cache.upsert(req, watcher_config.concurrency).await?;
+                let instance_id = dispatch.instance_id;
+
+                // `upsert` only ever dispatches to an instance that's
+                // never successfully built when the whole pool is in the
+                // same state — fail fast with the build error instead of
+                // queuing behind a process that's never coming up.
+                if let Some(build_error) = state.req_cache.build_failure(&function_name, instance_id).await {
+                    if let Some(resp_tx) = state.req_cache.take_response_channel(&req_id).await {
+                        let _ = resp_tx.send(InvokeResponse::Error(build_error));
                     }
+                    continue;
+                }
+
+                // `deadline_ms` is a placeholder here, same as
+                // `queue_invocation`'s doc comment explains: it's
+                // recomputed from `watcher_config.timeout` when this
+                // invocation is actually delivered via `next_invocation`,
+                // not from when it's merely queued.
+                let context = InvokeContext {
+                    aws_request_id: req_id.clone(),
+                    deadline_ms: 0,
+                    memory_limit_in_mb: watcher_config.memory_limit_in_mb,
+                    invoked_function_arn: watcher_config.function_arn.clone(),
+                };
+                state
+                    .req_cache
+                    .queue_invocation(
+                        &function_name,
+                        instance_id,
+                        req_id.clone(),
+                        payload,
+                        context,
+                        watcher_config.timeout,
+                    )
+                    .await;
+
+                if dispatch.spawned && !watcher_config.only_lambda_apis {
+                    let (terminate_tx, terminate_rx) = oneshot::channel();
+                    terminate_txs
+                        .register(function_name.clone(), instance_id, terminate_tx)
+                        .await;
+
+                    let gc_tx = gc_tx.clone();
+                    let cargo_options = cargo_options.clone();
+                    let watcher_config = watcher_config.clone();
+                    let ext_cache = state.ext_cache.clone();
+                    let wx_tx = wx_tx.clone();
+                    let function_name = function_name.clone();
+                    subsys.start("lambda runtime", move |s| {
+                        start_function(
+                            s,
+                            function_name,
+                            instance_id,
+                            dispatch.runtime_api,
+                            cargo_options,
+                            watcher_config,
+                            gc_tx,
+                            ext_cache,
+                            wx_tx,
+                            terminate_rx,
+                        )
+                    });
                 }
+
+                // The per-invocation timeout watcher itself is started by
+                // `runtime_api::next_invocation` once the dispatched
+                // instance actually dequeues this invocation, not here —
+                // see that function's doc comment for why.
             },
-            Some(gc) = gc_rx.recv() => {
-                state.req_cache.clean(&gc).await;
+            Some((name, instance_id)) = gc_rx.recv() => {
+                state.req_cache.clean(&name, instance_id).await;
+                terminate_txs.take(&name, instance_id).await;
             },
             _ = subsys.on_shutdown_requested() => {
                 info!("terminating lambda scheduler");
@@ -80,24 +200,65 @@ async fn start_scheduler(
     }
 }
 
+/// Fails and recycles an invocation that's still waiting on a response
+/// once `timeout` elapses. Called from
+/// [`crate::runtime_api::next_invocation`], whose doc comment explains why
+/// the clock starts there rather than when the invocation was queued.
+pub(crate) fn spawn_timeout_watcher(
+    req_cache: crate::state::RequestCache,
+    terminate_txs: TerminateRegistry,
+    function_name: String,
+    instance_id: usize,
+    req_id: String,
+    timeout: std::time::Duration,
+) {
+    tokio::spawn(async move {
+        tokio::time::sleep(timeout).await;
+
+        let Some(resp_tx) = req_cache.take_response_channel(&req_id).await else {
+            return;
+        };
+
+        warn!(
+            function = ?function_name,
+            instance = instance_id,
+            timeout = ?timeout,
+            "invocation timed out, recycling instance"
+        );
+
+        let _ = resp_tx.send(InvokeResponse::Error(format!(
+            "Task timed out after {:.2} seconds",
+            timeout.as_secs_f64()
+        )));
+
+        if let Some(terminate_tx) = terminate_txs.take(&function_name, instance_id).await {
+            let _ = terminate_tx.send(());
+        }
+    });
+}
+
+#[allow(clippy::too_many_arguments)]
 async fn start_function(
     subsys: SubsystemHandle,
     name: String,
+    instance_id: usize,
     runtime_api: String,
     cargo_options: CargoOptions,
     mut watcher_config: WatcherConfig,
-    gc_tx: Sender<String>,
+    gc_tx: Sender<(String, usize)>,
     ext_cache: ExtensionCache,
     wx_tx: Sender<(
         tokio::sync::oneshot::Sender<Result<Arc<Watchexec>, ServerError>>,
         Command,
         WatcherConfig,
-        ExtensionCache,
+        CargoOptions,
+        usize,
     )>,
+    terminate_rx: oneshot::Receiver<()>,
 ) -> Result<(), ServerError> {
-    info!(function = ?name, manifest = ?cargo_options.manifest_path, "starting lambda function");
+    info!(function = ?name, instance = instance_id, manifest = ?cargo_options.manifest_path, "starting lambda function");
 
-    let cmd = cargo_command(&name, &cargo_options);
+    let cmd = cargo_command(&name, &cargo_options, &watcher_config);
     watcher_config.bin_name = if is_valid_bin_name(&name) {
         Some(name.clone())
     } else {
@@ -105,19 +266,26 @@ async fn start_function(
     };
     watcher_config.name = name.clone();
     watcher_config.runtime_api = runtime_api;
+    watcher_config.manifest_path = cargo_options.manifest_path.clone();
 
     let (tx, rx) = oneshot::channel();
 
     let _ = wx_tx
-        .send((tx, cmd, watcher_config, ext_cache.clone()))
+        .send((tx, cmd, watcher_config, cargo_options, instance_id))
         .await;
 
     let wx = rx.blocking_recv()??;
 
     tokio::select! {
         _ = wx.main() => {
-            if let Err(err) = gc_tx.send(name.clone()).await {
-                error!(error = %err, function = ?name, "failed to send message to cleanup dead function");
+            if let Err(err) = gc_tx.send((name.clone(), instance_id)).await {
+                error!(error = %err, function = ?name, instance = instance_id, "failed to send message to cleanup dead function");
+            }
+        },
+        _ = terminate_rx => {
+            info!(function = ?name, instance = instance_id, "recycling instance after invocation timeout");
+            if let Err(err) = gc_tx.send((name.clone(), instance_id)).await {
+                error!(error = %err, function = ?name, instance = instance_id, "failed to send message to cleanup recycled function");
             }
         },
         _ = subsys.on_shutdown_requested() => {
@@ -133,8 +301,12 @@ fn is_valid_bin_name(name: &str) -> bool {
     !name.is_empty() && name != DEFAULT_PACKAGE_FUNCTION
 }
 
-fn cargo_command(name: &str, cargo_options: &CargoOptions) -> watchexec::command::Command {
-    let mut args = vec!["run".into()];
+fn cargo_command(
+    name: &str,
+    cargo_options: &CargoOptions,
+    watcher_config: &WatcherConfig,
+) -> watchexec::command::Command {
+    let mut args: Vec<String> = vec!["run".into()];
     if let Some(features) = cargo_options.features.as_deref() {
         args.push("--features".into());
         args.push(features.into());
@@ -149,8 +321,129 @@ fn cargo_command(name: &str, cargo_options: &CargoOptions) -> watchexec::command
         args.push(name.into());
     }
 
-    Command::Exec {
-        prog: "cargo".into(),
-        args,
+    let mut envs: Vec<(String, String)> = watcher_config
+        .env_vars
+        .iter()
+        .map(|(key, value)| (key.clone(), value.clone()))
+        .collect();
+    if watcher_config.memory_limit_in_mb > 0 {
+        envs.push((
+            "AWS_LAMBDA_FUNCTION_MEMORY_SIZE".into(),
+            watcher_config.memory_limit_in_mb.to_string(),
+        ));
+    }
+
+    if envs.is_empty() {
+        Command::Exec {
+            prog: "cargo".into(),
+            args,
+        }
+    } else {
+        // Watchexec's `Command::Exec` has no env field of its own. Rather
+        // than shelling out to the `env` utility to set it — which has no
+        // equivalent shipped on Windows — set it directly in a command
+        // string native to the host platform's own shell instead. Every
+        // value is quoted unconditionally (never interpolated raw), since
+        // an env value or arg is arbitrary user input from `--env`/
+        // `--env-file`/`--features`, not something this emulator can
+        // assume is "plain".
+        let (shell, command) = if cfg!(windows) {
+            let quoted_args = args
+                .iter()
+                .map(|arg| powershell_quote(arg))
+                .collect::<Vec<_>>()
+                .join(" ");
+            let assignments: String = envs
+                .iter()
+                .map(|(key, value)| format!("$env:{key} = {}; ", powershell_quote(value)))
+                .collect();
+            (
+                Shell::Powershell,
+                format!("{assignments}cargo {quoted_args}"),
+            )
+        } else {
+            let quoted_args = args
+                .iter()
+                .map(|arg| posix_quote(arg))
+                .collect::<Vec<_>>()
+                .join(" ");
+            let assignments: String = envs
+                .iter()
+                .map(|(key, value)| format!("{key}={} ", posix_quote(value)))
+                .collect();
+            (
+                Shell::Unix("sh".into()),
+                format!("{assignments}cargo {quoted_args}"),
+            )
+        };
+
+        Command::Shell { shell, command }
+    }
+}
+
+/// Quotes `value` for safe interpolation into the POSIX shell command
+/// string [`cargo_command`] builds to set environment variables ahead of
+/// `cargo run`: wraps it in single quotes, which suppress every shell
+/// metacharacter including `$()`/backtick command substitution, and
+/// escapes an embedded single quote as `'\''` (close the quote, an
+/// escaped literal quote, reopen the quote).
+fn posix_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', r"'\''"))
+}
+
+/// Quotes `value` for safe interpolation into the PowerShell command
+/// string [`cargo_command`] builds on Windows: wraps it in single quotes,
+/// which PowerShell treats as a literal string with no interpolation or
+/// metacharacter handling, and escapes an embedded single quote by
+/// doubling it.
+fn powershell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "''"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::Command as StdCommand;
+
+    #[test]
+    fn posix_quote_neutralizes_command_substitution() {
+        let payload = "$(echo INJECTED)";
+        let output = StdCommand::new("sh")
+            .arg("-c")
+            .arg(format!("X={} ; printf '%s' \"$X\"", posix_quote(payload)))
+            .output()
+            .expect("sh must be available to run this test");
+
+        assert_eq!(
+            String::from_utf8_lossy(&output.stdout),
+            payload,
+            "the payload must be set literally, not executed as a command substitution"
+        );
+    }
+
+    #[test]
+    fn posix_quote_neutralizes_trailing_backslash() {
+        let payload = r"trailing\";
+        let output = StdCommand::new("sh")
+            .arg("-c")
+            .arg(format!("X={} ; printf '%s' \"$X\"", posix_quote(payload)))
+            .output()
+            .expect("sh must be available to run this test");
+
+        assert_eq!(
+            String::from_utf8_lossy(&output.stdout),
+            payload,
+            "a trailing backslash must not escape the closing quote"
+        );
+    }
+
+    #[test]
+    fn posix_quote_escapes_embedded_single_quote() {
+        assert_eq!(posix_quote("it's"), r"'it'\''s'");
+    }
+
+    #[test]
+    fn powershell_quote_escapes_embedded_single_quote() {
+        assert_eq!(powershell_quote("it's"), "'it''s'");
     }
 }
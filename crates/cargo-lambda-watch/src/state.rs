@@ -0,0 +1,887 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use serde_json::Value;
+use tokio::sync::{oneshot, Mutex, RwLock};
+
+use crate::{
+    error::ServerError,
+    event_source::SourceQueue,
+    requests::{InvokeContext, InvokeRequest, InvokeResponse, NextEvent},
+};
+
+/// How long a warm instance beyond the pool's first can sit idle before
+/// it's garbage-collected.
+const IDLE_INSTANCE_TTL: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Clone, Default)]
+pub(crate) struct RuntimeState {
+    pub(crate) req_cache: RequestCache,
+    pub(crate) ext_cache: ExtensionCache,
+    pub(crate) source_queues: SourceQueueRegistry,
+    pub(crate) terminate_txs: TerminateRegistry,
+}
+
+/// A function's runtime API address was just assigned to a brand new
+/// warm instance, or an existing instance was picked via round-robin.
+pub(crate) struct Dispatch {
+    pub(crate) instance_id: usize,
+    pub(crate) runtime_api: String,
+    pub(crate) spawned: bool,
+}
+
+/// Tracks each function's pool of warm instances, their runtime API
+/// addresses, and the reply channel each in-flight invocation is waiting
+/// on.
+#[derive(Clone, Default)]
+pub(crate) struct RequestCache {
+    inner: Arc<RwLock<Inner>>,
+}
+
+#[derive(Default)]
+struct Inner {
+    functions: HashMap<String, FunctionPool>,
+    pending: HashMap<String, oneshot::Sender<InvokeResponse>>,
+    /// Which function/instance each still-pending `req_id` was dispatched
+    /// to, so [`RequestCache::take_response_channel`] can clear the
+    /// instance's busy flag once its response is taken, wherever that
+    /// happens to be called from (the runtime API's response handler, a
+    /// timeout, or a failed rebuild).
+    req_instance: HashMap<String, (String, usize)>,
+}
+
+/// The warm instances currently running for one function, round-robined
+/// across as invocations arrive. Instances are keyed by a monotonic id
+/// that's never reused, so an id removed by [`RequestCache::clean`] can't
+/// collide with one a later spawn hands out — unlike a `Vec` index, which
+/// shifts every instance after the removed one down a slot.
+struct FunctionPool {
+    runtime_apis: HashMap<usize, String>,
+    next_instance_id: usize,
+    round_robin: usize,
+    /// When each instance last had an invocation dispatched to it, so
+    /// [`RequestCache::idle_instances`] can judge idleness per instance
+    /// instead of one instance's traffic keeping the whole pool looking
+    /// busy — or a quiet pool GC'ing an instance that's still grinding
+    /// through a long-running invocation.
+    last_used: HashMap<usize, Instant>,
+    /// Invocations queued for each instance, in arrival order. An instance
+    /// only ever has one in flight at a time — handed out by the front of
+    /// its queue on `GET .../invocation/next` and popped there, so a
+    /// second poll with nothing new returns `NO_CONTENT` instead of
+    /// redelivering it, and an invocation that arrives while the instance
+    /// is still busy queues behind it instead of clobbering it.
+    current: HashMap<usize, VecDeque<PendingInvocation>>,
+    /// Instances that have been handed an invocation (via
+    /// [`RequestCache::next_invocation`]) but haven't replied yet. An
+    /// instance's queue empties the moment it polls for its invocation,
+    /// long before it's actually done processing it, so idleness for
+    /// dispatch purposes is tracked here rather than by queue occupancy —
+    /// otherwise every request would pile onto whichever instance most
+    /// recently polled instead of spreading across the warm pool.
+    busy: HashSet<usize>,
+    /// The most recent build failure for an instance that's never
+    /// successfully started, set by
+    /// [`RequestCache::mark_build_failed`] and cleared by
+    /// [`RequestCache::mark_build_succeeded`]. [`upsert`](RequestCache::upsert)
+    /// treats these instances as unhealthy and only ever routes to one when
+    /// there's nowhere else to send an invocation, so `start_scheduler` can
+    /// fail it immediately with the recorded error instead of queuing it
+    /// behind a process that's never coming up.
+    build_errors: HashMap<usize, String>,
+}
+
+impl Default for FunctionPool {
+    fn default() -> Self {
+        FunctionPool {
+            runtime_apis: HashMap::new(),
+            next_instance_id: 0,
+            round_robin: 0,
+            last_used: HashMap::new(),
+            current: HashMap::new(),
+            busy: HashSet::new(),
+            build_errors: HashMap::new(),
+        }
+    }
+}
+
+/// The invocation queued for one instance to pick up on its next poll,
+/// carrying the `Context` it should see alongside the event payload.
+#[derive(Clone)]
+struct PendingInvocation {
+    req_id: String,
+    payload: Value,
+    /// `deadline_ms` is a placeholder here: it's overwritten with a deadline
+    /// computed from `timeout` no earlier than [`RequestCache::next_invocation`]
+    /// actually delivers this invocation, not when it was queued — otherwise an
+    /// invocation that waits behind an already-busy instance could have its
+    /// deadline (and timeout watcher) start counting down before the instance
+    /// is even free to work on it.
+    context: InvokeContext,
+    timeout: Duration,
+}
+
+impl RequestCache {
+    /// Registers `req` and decides which instance of its function should
+    /// serve it: reuses an already-registered, healthy instance that's
+    /// sitting idle (nothing queued for it *and* not still processing a
+    /// previously delivered invocation) if one exists, only spins up a new
+    /// instance, with its own runtime API registration, once every existing
+    /// instance is busy and the pool is still under `concurrency`, and
+    /// otherwise round-robins across the healthy instances already running.
+    /// An instance with a recorded [`build_errors`](FunctionPool::build_errors)
+    /// entry is only ever picked once the pool is at capacity and every
+    /// instance is in the same state, so [`build_failure`](Self::build_failure)
+    /// can tell `start_scheduler` to fail the invocation immediately instead
+    /// of queuing it behind a process that's never coming up.
+    pub(crate) async fn upsert(
+        &self,
+        req: InvokeRequest,
+        concurrency: usize,
+    ) -> Result<Dispatch, ServerError> {
+        let mut inner = self.inner.write().await;
+        let function_name = req.function_name.clone();
+        let req_id = req.req_id.clone();
+
+        let pool = inner.functions.entry(function_name.clone()).or_default();
+
+        let mut ids: Vec<usize> = pool.runtime_apis.keys().copied().collect();
+        ids.sort_unstable();
+        let healthy_ids: Vec<usize> = ids
+            .iter()
+            .copied()
+            .filter(|id| !pool.build_errors.contains_key(id))
+            .collect();
+
+        let idle_instance = healthy_ids.iter().copied().find(|id| {
+            !pool.busy.contains(id) && pool.current.get(id).map_or(true, VecDeque::is_empty)
+        });
+
+        let dispatch = if let Some(instance_id) = idle_instance {
+            Dispatch {
+                instance_id,
+                runtime_api: pool.runtime_apis[&instance_id].clone(),
+                spawned: false,
+            }
+        } else if pool.runtime_apis.len() < concurrency.max(1) {
+            let instance_id = pool.next_instance_id;
+            pool.next_instance_id += 1;
+            let runtime_api = format!("/{function_name}/{instance_id}/runtime");
+            pool.runtime_apis.insert(instance_id, runtime_api.clone());
+            Dispatch {
+                instance_id,
+                runtime_api,
+                spawned: true,
+            }
+        } else if !healthy_ids.is_empty() {
+            let instance_id = healthy_ids[pool.round_robin % healthy_ids.len()];
+            pool.round_robin = pool.round_robin.wrapping_add(1);
+            Dispatch {
+                instance_id,
+                runtime_api: pool.runtime_apis[&instance_id].clone(),
+                spawned: false,
+            }
+        } else {
+            // Every instance registered for this function has failed to
+            // ever start successfully and the pool is at its concurrency
+            // limit, so there's nowhere healthy to route this invocation.
+            // Round-robin across them anyway: `start_scheduler` checks
+            // `build_failure` right after dispatch and fails the
+            // invocation immediately with the recorded error instead of
+            // calling `queue_invocation`.
+            let instance_id = ids[pool.round_robin % ids.len()];
+            pool.round_robin = pool.round_robin.wrapping_add(1);
+            Dispatch {
+                instance_id,
+                runtime_api: pool.runtime_apis[&instance_id].clone(),
+                spawned: false,
+            }
+        };
+
+        pool.last_used.insert(dispatch.instance_id, Instant::now());
+
+        inner.pending.insert(req_id.clone(), req.resp_tx);
+        inner
+            .req_instance
+            .insert(req_id, (function_name, dispatch.instance_id));
+
+        Ok(dispatch)
+    }
+
+    /// Queues `payload`/`context` as an invocation for `instance_id` to pick
+    /// up, behind any invocation it's already serving or hasn't yet polled
+    /// for. `timeout` is kept alongside `context` rather than baked into its
+    /// `deadline_ms` right away, since [`Self::next_invocation`] is what
+    /// actually starts that clock.
+    pub(crate) async fn queue_invocation(
+        &self,
+        function_name: &str,
+        instance_id: usize,
+        req_id: String,
+        payload: Value,
+        context: InvokeContext,
+        timeout: Duration,
+    ) {
+        let mut inner = self.inner.write().await;
+        if let Some(pool) = inner.functions.get_mut(function_name) {
+            pool.current
+                .entry(instance_id)
+                .or_default()
+                .push_back(PendingInvocation {
+                    req_id,
+                    payload,
+                    context,
+                    timeout,
+                });
+        }
+    }
+
+    /// Pops the next invocation queued for `instance_id`, if any, as
+    /// `(req_id, payload, context, timeout)`, with `context.deadline_ms`
+    /// computed fresh from this delivery, not from when it was queued —
+    /// so a request that waited behind a busy instance gets the same
+    /// generous deadline as one dispatched straight away. Each invocation
+    /// is only ever returned once: a later poll with nothing new queued
+    /// gets `None`. Marks the instance busy until its response is taken, so
+    /// [`Self::upsert`] doesn't treat it as idle again the moment its queue
+    /// empties.
+    pub(crate) async fn next_invocation(
+        &self,
+        function_name: &str,
+        instance_id: usize,
+    ) -> Option<(String, Value, InvokeContext, Duration)> {
+        let mut inner = self.inner.write().await;
+        let pool = inner.functions.get_mut(function_name)?;
+        let pending = pool.current.get_mut(&instance_id)?.pop_front()?;
+        pool.busy.insert(instance_id);
+
+        let mut context = pending.context;
+        context.deadline_ms = deadline_ms(pending.timeout);
+
+        Some((pending.req_id, pending.payload, context, pending.timeout))
+    }
+
+    /// Resolves every invocation currently queued for `instance_id` with an
+    /// error, e.g. when a rebuild ahead of a reload fails and the instance
+    /// that would have served them never comes up. A single failed rebuild
+    /// can leave several invocations stacked up behind the same busy
+    /// instance, so the whole queue is drained and failed, the same way
+    /// [`clean`](Self::clean) handles a removed instance's queue. Returns
+    /// whether any invocation was actually waiting.
+    pub(crate) async fn fail_current(
+        &self,
+        function_name: &str,
+        instance_id: usize,
+        message: String,
+    ) -> bool {
+        let queue = {
+            let mut inner = self.inner.write().await;
+            inner
+                .functions
+                .get_mut(function_name)
+                .and_then(|pool| pool.current.get_mut(&instance_id))
+                .map(std::mem::take)
+                .unwrap_or_default()
+        };
+
+        let mut any_failed = false;
+        for pending in queue {
+            let Some(resp_tx) = self.take_response_channel(&pending.req_id).await else {
+                continue;
+            };
+
+            let _ = resp_tx.send(InvokeResponse::Error(message.clone()));
+            any_failed = true;
+        }
+
+        any_failed
+    }
+
+    /// Takes the reply channel registered for `req_id`, if the invocation
+    /// is still waiting on a response, and clears the busy flag on the
+    /// instance it was dispatched to.
+    pub(crate) async fn take_response_channel(
+        &self,
+        req_id: &str,
+    ) -> Option<oneshot::Sender<InvokeResponse>> {
+        let mut inner = self.inner.write().await;
+        let resp_tx = inner.pending.remove(req_id)?;
+
+        if let Some((function_name, instance_id)) = inner.req_instance.remove(req_id) {
+            if let Some(pool) = inner.functions.get_mut(&function_name) {
+                pool.busy.remove(&instance_id);
+            }
+        }
+
+        Some(resp_tx)
+    }
+
+    /// Records that `instance_id` just failed to build, so
+    /// [`upsert`](Self::upsert) stops routing new invocations to it ahead
+    /// of a healthy one and [`build_failure`](Self::build_failure) can
+    /// report why. Stays recorded until [`mark_build_succeeded`](Self::mark_build_succeeded)
+    /// clears it or the instance is recycled via [`clean`](Self::clean).
+    pub(crate) async fn mark_build_failed(&self, name: &str, instance_id: usize, message: String) {
+        let mut inner = self.inner.write().await;
+        if let Some(pool) = inner.functions.get_mut(name) {
+            pool.build_errors.insert(instance_id, message);
+        }
+    }
+
+    /// Clears a previously recorded build failure for `instance_id`, e.g.
+    /// once an edit that fixes the build reloads it successfully.
+    pub(crate) async fn mark_build_succeeded(&self, name: &str, instance_id: usize) {
+        let mut inner = self.inner.write().await;
+        if let Some(pool) = inner.functions.get_mut(name) {
+            pool.build_errors.remove(&instance_id);
+        }
+    }
+
+    /// The build error last recorded for `instance_id` via
+    /// [`mark_build_failed`](Self::mark_build_failed), if any. `upsert`
+    /// only ever dispatches to such an instance when the whole pool is in
+    /// the same state, so `start_scheduler` checks this right after
+    /// dispatch to fail the invocation immediately instead of queuing it.
+    pub(crate) async fn build_failure(&self, name: &str, instance_id: usize) -> Option<String> {
+        let inner = self.inner.read().await;
+        inner
+            .functions
+            .get(name)
+            .and_then(|pool| pool.build_errors.get(&instance_id).cloned())
+    }
+
+    /// Drops the instance registered for `name`/`instance_id`, e.g. after
+    /// its process died or it idled past [`IDLE_INSTANCE_TTL`]. Every
+    /// invocation belonging to that instance is failed first, whether it's
+    /// still queued or already handed out via [`next_invocation`](Self::next_invocation)
+    /// and tracked only through `req_instance`, the same way
+    /// [`fail_current`](Self::fail_current) resolves a queued one, so a
+    /// crashed or recycled instance can't leave a `cargo lambda invoke`
+    /// client waiting forever for a response that's never coming.
+    pub(crate) async fn clean(&self, name: &str, instance_id: usize) {
+        let mut inner = self.inner.write().await;
+        let mut queue = VecDeque::new();
+        if let Some(pool) = inner.functions.get_mut(name) {
+            pool.runtime_apis.remove(&instance_id);
+            pool.busy.remove(&instance_id);
+            pool.last_used.remove(&instance_id);
+            pool.build_errors.remove(&instance_id);
+            queue = pool.current.remove(&instance_id).unwrap_or_default();
+            if pool.runtime_apis.is_empty() {
+                inner.functions.remove(name);
+            }
+        }
+
+        let mut req_ids: HashSet<String> =
+            queue.into_iter().map(|pending| pending.req_id).collect();
+        req_ids.extend(
+            inner
+                .req_instance
+                .iter()
+                .filter_map(|(req_id, (fname, iid))| {
+                    (fname == name && *iid == instance_id).then(|| req_id.clone())
+                }),
+        );
+
+        for req_id in req_ids {
+            inner.req_instance.remove(&req_id);
+            if let Some(resp_tx) = inner.pending.remove(&req_id) {
+                let _ = resp_tx.send(InvokeResponse::Error(
+                    "instance recycled before this invocation could complete".into(),
+                ));
+            }
+        }
+    }
+
+    /// Every extra warm instance (beyond the one with the lowest id) that's
+    /// itself had no invocation dispatched to it for longer than
+    /// [`IDLE_INSTANCE_TTL`], as `(function_name, instance_id)` pairs ready
+    /// to pass to [`clean`]. An instance that's still busy or has
+    /// invocations queued behind it is never nominated, even if the rest of
+    /// its pool has gone quiet — idleness is judged per instance, not by
+    /// whether *some* instance in the pool has seen recent traffic.
+    pub(crate) async fn idle_instances(&self) -> Vec<(String, usize)> {
+        let inner = self.inner.read().await;
+        inner
+            .functions
+            .iter()
+            .flat_map(|(name, pool)| {
+                let mut ids: Vec<usize> = pool.runtime_apis.keys().copied().collect();
+                ids.sort_unstable();
+                ids.into_iter()
+                    .skip(1)
+                    .filter(|id| {
+                        !pool.busy.contains(id)
+                            && pool.current.get(id).map_or(true, VecDeque::is_empty)
+                            && pool
+                                .last_used
+                                .get(id)
+                                .map_or(false, |last_used| last_used.elapsed() > IDLE_INSTANCE_TTL)
+                    })
+                    .map(|id| (name.clone(), id))
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    }
+}
+
+/// Milliseconds since the Unix epoch by which an invocation with `timeout`
+/// must return, for `lambda_runtime::Context::deadline`. A `timeout` of
+/// `Duration::ZERO` means no deadline is enforced, so the handler gets a
+/// generous default instead of an already-past one.
+fn deadline_ms(timeout: Duration) -> u64 {
+    const NO_TIMEOUT_DEFAULT: Duration = Duration::from_secs(15 * 60);
+
+    let timeout = if timeout.is_zero() {
+        NO_TIMEOUT_DEFAULT
+    } else {
+        timeout
+    };
+
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .saturating_add(timeout)
+        .as_millis() as u64
+}
+
+/// Looks up the [`SourceQueue`] backing a function's event source mappings
+/// by `(function_name, index)`, so `runtime_api::push_event_source_record`
+/// can feed a local event source from outside the process.
+#[derive(Clone, Default)]
+pub(crate) struct SourceQueueRegistry {
+    inner: Arc<RwLock<HashMap<(String, usize), SourceQueue>>>,
+}
+
+impl SourceQueueRegistry {
+    pub(crate) async fn register(&self, function_name: &str, index: usize, queue: SourceQueue) {
+        self.inner
+            .write()
+            .await
+            .insert((function_name.to_owned(), index), queue);
+    }
+
+    pub(crate) async fn get(&self, function_name: &str, index: usize) -> Option<SourceQueue> {
+        self.inner
+            .read()
+            .await
+            .get(&(function_name.to_owned(), index))
+            .cloned()
+    }
+}
+
+/// Fan-out point for Lambda Extensions API events, shared by every
+/// instance of a given function.
+#[derive(Clone, Default)]
+pub(crate) struct ExtensionCache {
+    inner: Arc<RwLock<Vec<NextEvent>>>,
+}
+
+impl ExtensionCache {
+    pub(crate) async fn send_event(&self, event: NextEvent) -> Result<(), ServerError> {
+        self.inner.write().await.push(event);
+        Ok(())
+    }
+}
+
+/// Which channel to send on to recycle a spawned instance early, keyed by
+/// `(function_name, instance_id)`. Registered by `start_scheduler` when it
+/// spawns an instance's process, and taken either by a timeout watcher
+/// spawned from [`crate::runtime_api::next_invocation`] once that instance's
+/// invocation actually times out, or by `start_scheduler`'s cleanup when the
+/// instance is otherwise recycled.
+#[derive(Clone, Default)]
+pub(crate) struct TerminateRegistry {
+    inner: Arc<Mutex<HashMap<(String, usize), oneshot::Sender<()>>>>,
+}
+
+impl TerminateRegistry {
+    pub(crate) async fn register(
+        &self,
+        function_name: String,
+        instance_id: usize,
+        terminate_tx: oneshot::Sender<()>,
+    ) {
+        self.inner
+            .lock()
+            .await
+            .insert((function_name, instance_id), terminate_tx);
+    }
+
+    /// Removes and returns the registered sender for `function_name`/
+    /// `instance_id`, if one is still registered.
+    pub(crate) async fn take(
+        &self,
+        function_name: &str,
+        instance_id: usize,
+    ) -> Option<oneshot::Sender<()>> {
+        self.inner
+            .lock()
+            .await
+            .remove(&(function_name.to_owned(), instance_id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn context() -> InvokeContext {
+        InvokeContext {
+            aws_request_id: "req-1".into(),
+            deadline_ms: 0,
+            memory_limit_in_mb: 128,
+            invoked_function_arn: "arn:aws:lambda:us-east-1:000000000000:function:test".into(),
+        }
+    }
+
+    #[tokio::test]
+    async fn next_invocation_delivers_once() {
+        let cache = RequestCache::default();
+        let (resp_tx, resp_rx) = oneshot::channel();
+        drop(resp_rx);
+
+        cache
+            .upsert(
+                InvokeRequest {
+                    req_id: "req-1".into(),
+                    function_name: "test".into(),
+                    payload: Value::Null,
+                    resp_tx,
+                },
+                1,
+            )
+            .await
+            .unwrap();
+
+        cache
+            .queue_invocation(
+                "test",
+                0,
+                "req-1".into(),
+                serde_json::json!({"hello": "world"}),
+                context(),
+                Duration::ZERO,
+            )
+            .await;
+
+        let (req_id, payload, _, _) = cache
+            .next_invocation("test", 0)
+            .await
+            .expect("queued invocation should be delivered");
+        assert_eq!(req_id, "req-1");
+        assert_eq!(payload, serde_json::json!({"hello": "world"}));
+
+        assert!(
+            cache.next_invocation("test", 0).await.is_none(),
+            "a second poll with nothing new queued must not redeliver the invocation"
+        );
+    }
+
+    #[tokio::test]
+    async fn next_invocation_computes_deadline_from_delivery_not_enqueue_time() {
+        let cache = RequestCache::default();
+        let (resp_tx, resp_rx) = oneshot::channel();
+        drop(resp_rx);
+
+        cache
+            .upsert(
+                InvokeRequest {
+                    req_id: "req-1".into(),
+                    function_name: "test".into(),
+                    payload: Value::Null,
+                    resp_tx,
+                },
+                1,
+            )
+            .await
+            .unwrap();
+
+        let timeout = Duration::from_secs(30);
+        cache
+            .queue_invocation("test", 0, "req-1".into(), Value::Null, context(), timeout)
+            .await;
+
+        // Stand in for the invocation sitting queued behind an already-busy
+        // instance for a while before it's actually delivered.
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let before_delivery = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64;
+
+        let (_, _, delivered_context, delivered_timeout) = cache
+            .next_invocation("test", 0)
+            .await
+            .expect("queued invocation should be delivered");
+
+        assert_eq!(delivered_timeout, timeout);
+        assert!(
+            delivered_context.deadline_ms >= before_delivery + timeout.as_millis() as u64,
+            "deadline must be computed from delivery time, not from when the invocation was \
+             queued, or a request that waited behind a busy instance would get an unfairly \
+             short deadline"
+        );
+    }
+
+    #[tokio::test]
+    async fn clean_fails_queued_invocations() {
+        let cache = RequestCache::default();
+        let (resp_tx, resp_rx) = oneshot::channel();
+
+        cache
+            .upsert(
+                InvokeRequest {
+                    req_id: "req-1".into(),
+                    function_name: "test".into(),
+                    payload: Value::Null,
+                    resp_tx,
+                },
+                1,
+            )
+            .await
+            .unwrap();
+
+        cache
+            .queue_invocation(
+                "test",
+                0,
+                "req-1".into(),
+                serde_json::json!({"hello": "world"}),
+                context(),
+                Duration::ZERO,
+            )
+            .await;
+
+        cache.clean("test", 0).await;
+
+        match resp_rx
+            .await
+            .expect("clean must resolve the waiting client")
+        {
+            InvokeResponse::Error(_) => {}
+            _ => panic!("a recycled instance's queued invocations must fail, not hang"),
+        }
+    }
+
+    #[tokio::test]
+    async fn clean_fails_in_flight_invocation_not_just_queued_ones() {
+        let cache = RequestCache::default();
+        let (resp_tx, resp_rx) = oneshot::channel();
+
+        cache
+            .upsert(
+                InvokeRequest {
+                    req_id: "req-1".into(),
+                    function_name: "test".into(),
+                    payload: Value::Null,
+                    resp_tx,
+                },
+                1,
+            )
+            .await
+            .unwrap();
+
+        cache
+            .queue_invocation(
+                "test",
+                0,
+                "req-1".into(),
+                serde_json::json!({"hello": "world"}),
+                context(),
+                Duration::ZERO,
+            )
+            .await;
+
+        cache
+            .next_invocation("test", 0)
+            .await
+            .expect("the instance should pick up its queued invocation");
+
+        cache.clean("test", 0).await;
+
+        match resp_rx
+            .await
+            .expect("clean must resolve the waiting client")
+        {
+            InvokeResponse::Error(_) => {}
+            _ => panic!(
+                "a recycled instance's in-flight invocation, tracked only via req_instance, \
+                 must fail, not hang"
+            ),
+        }
+    }
+
+    fn invoke_request(req_id: &str) -> (InvokeRequest, oneshot::Receiver<InvokeResponse>) {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        (
+            InvokeRequest {
+                req_id: req_id.into(),
+                function_name: "test".into(),
+                payload: Value::Null,
+                resp_tx,
+            },
+            resp_rx,
+        )
+    }
+
+    #[tokio::test]
+    async fn upsert_spawns_while_busy_under_concurrency_and_round_robins_at_limit() {
+        let cache = RequestCache::default();
+
+        let (req, _rx) = invoke_request("req-1");
+        let first = cache.upsert(req, 2).await.unwrap();
+        assert_eq!(first.instance_id, 0);
+        assert!(first.spawned, "first invocation must spawn a new instance");
+        cache
+            .queue_invocation(
+                "test",
+                0,
+                "req-1".into(),
+                Value::Null,
+                context(),
+                Duration::ZERO,
+            )
+            .await;
+
+        let (req, _rx) = invoke_request("req-2");
+        let second = cache.upsert(req, 2).await.unwrap();
+        assert_eq!(
+            second.instance_id, 1,
+            "instance 0 is busy, so a second warm instance should be spawned under concurrency"
+        );
+        assert!(second.spawned);
+        cache
+            .queue_invocation(
+                "test",
+                1,
+                "req-2".into(),
+                Value::Null,
+                context(),
+                Duration::ZERO,
+            )
+            .await;
+
+        let (req, _rx) = invoke_request("req-3");
+        let third = cache.upsert(req, 2).await.unwrap();
+        assert_eq!(
+            third.instance_id, 0,
+            "both instances are busy and the pool is at its concurrency limit, so dispatch must round-robin"
+        );
+        assert!(!third.spawned);
+    }
+
+    #[tokio::test]
+    async fn upsert_tracks_busy_from_dispatch_through_response_not_queue_occupancy() {
+        let cache = RequestCache::default();
+
+        let (req, _rx) = invoke_request("req-1");
+        let first = cache.upsert(req, 2).await.unwrap();
+        assert_eq!(first.instance_id, 0);
+        assert!(first.spawned, "first invocation must spawn a new instance");
+        cache
+            .queue_invocation(
+                "test",
+                0,
+                "req-1".into(),
+                Value::Null,
+                context(),
+                Duration::ZERO,
+            )
+            .await;
+
+        let (req, _rx) = invoke_request("req-2");
+        let second = cache.upsert(req, 2).await.unwrap();
+        assert_eq!(
+            second.instance_id, 1,
+            "instance 0 is busy, so a second warm instance should be spawned under concurrency"
+        );
+        cache
+            .queue_invocation(
+                "test",
+                1,
+                "req-2".into(),
+                Value::Null,
+                context(),
+                Duration::ZERO,
+            )
+            .await;
+
+        cache
+            .next_invocation("test", 0)
+            .await
+            .expect("instance 0's queued invocation should be delivered");
+
+        let (req, _rx) = invoke_request("req-3");
+        let third = cache.upsert(req, 2).await.unwrap();
+        assert_eq!(
+            third.instance_id, 0,
+            "neither instance is idle (0 is still processing its delivered invocation, 1 has \
+             one queued) and the pool is at its concurrency limit, so dispatch round-robins, \
+             starting from instance 0"
+        );
+        assert!(!third.spawned);
+
+        let resp_tx = cache
+            .take_response_channel("req-1")
+            .await
+            .expect("instance 0's invocation should still be waiting on a response");
+        drop(resp_tx);
+
+        let (req, _rx) = invoke_request("req-4");
+        let fourth = cache.upsert(req, 2).await.unwrap();
+        assert_eq!(
+            fourth.instance_id, 0,
+            "instance 0 is idle again once its response has been taken, so it should be reused"
+        );
+        assert!(!fourth.spawned);
+    }
+
+    #[tokio::test]
+    async fn fail_current_fails_every_queued_invocation_not_just_the_first() {
+        let cache = RequestCache::default();
+
+        let (req, rx1) = invoke_request("req-1");
+        cache.upsert(req, 1).await.unwrap();
+        cache
+            .queue_invocation(
+                "test",
+                0,
+                "req-1".into(),
+                Value::Null,
+                context(),
+                Duration::ZERO,
+            )
+            .await;
+
+        let (req, rx2) = invoke_request("req-2");
+        cache.upsert(req, 1).await.unwrap();
+        cache
+            .queue_invocation(
+                "test",
+                0,
+                "req-2".into(),
+                Value::Null,
+                context(),
+                Duration::ZERO,
+            )
+            .await;
+
+        let failed = cache.fail_current("test", 0, "build failed".into()).await;
+        assert!(failed);
+
+        for rx in [rx1, rx2] {
+            match rx
+                .await
+                .expect("every invocation queued for the instance must be resolved")
+            {
+                InvokeResponse::Error(_) => {}
+                _ => panic!("invocations behind a failed rebuild must fail, not hang"),
+            }
+        }
+    }
+}
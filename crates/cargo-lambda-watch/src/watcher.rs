@@ -0,0 +1,347 @@
+use std::{
+    collections::HashMap, path::PathBuf, process::Command as StdCommand, sync::Arc, time::Duration,
+};
+
+use tokio::sync::mpsc::Sender;
+use tracing::error;
+use watchexec::{action::Outcome, command::Command, signal::process::Signal, Watchexec};
+
+use crate::{error::ServerError, event_source::SourceQueue, state::RequestCache, CargoOptions};
+
+/// Per-function configuration for the `cargo lambda watch` emulator,
+/// threaded from the CLI down to each spawned function instance.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct WatcherConfig {
+    pub(crate) name: String,
+    pub(crate) bin_name: Option<String>,
+    pub(crate) runtime_api: String,
+    pub(crate) only_lambda_apis: bool,
+    pub(crate) watch_paths: Vec<PathBuf>,
+    /// Event source mappings to emulate for this function, polled
+    /// alongside the synchronous HTTP invoke path.
+    pub(crate) event_sources: Vec<EventSourceConfig>,
+    /// How many warm instances to keep running for this function at
+    /// once, round-robining invocations across them. Defaults to `1`.
+    pub(crate) concurrency: usize,
+    /// What to do about a file change that arrives while an invocation is
+    /// still in flight.
+    pub(crate) on_busy_update: OnBusyUpdate,
+    /// How long [`OnBusyUpdate::Signal`] waits for the process to exit on
+    /// its own after the stop signal before it's killed and restarted.
+    pub(crate) stop_timeout: Duration,
+    /// `cargo build`'s manifest path, used to rebuild ahead of restarting
+    /// so a failing build can be reported without tearing down the
+    /// instance that's still serving invocations.
+    pub(crate) manifest_path: Option<PathBuf>,
+    /// Environment variables to set on the function's process, collected
+    /// from `--env`/`--env-file` and the function's `samconfig`/template
+    /// section.
+    pub(crate) env_vars: HashMap<String, String>,
+    /// `AWS_LAMBDA_FUNCTION_MEMORY_SIZE`, in MB.
+    pub(crate) memory_limit_in_mb: i32,
+    /// How long an invocation may run before the scheduler times it out.
+    pub(crate) timeout: Duration,
+    /// The ARN invocations of this function are attributed to in their
+    /// `Context`.
+    pub(crate) function_arn: String,
+}
+
+/// Watchexec's busy-update semantics: what happens when a watched path
+/// changes while the function's process is still running an invocation.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) enum OnBusyUpdate {
+    /// Wait for the running process to exit, then reload. The wait is on
+    /// the process as a whole, not on any single invocation it serves.
+    #[default]
+    Queue,
+    /// Ignore the change; keep serving on the current process until it
+    /// exits on its own.
+    DoNothing,
+    /// Kill the process immediately and reload, aborting the in-flight
+    /// invocation.
+    Restart,
+    /// Send a graceful stop signal, give the process `stop_timeout` to
+    /// exit, then kill and reload.
+    Signal,
+}
+
+/// One emulated event source mapping: where records come from, how many
+/// to batch together, and how to handle a batch the function fails.
+#[derive(Clone, Debug)]
+pub(crate) struct EventSourceConfig {
+    pub(crate) kind: EventSourceKind,
+    pub(crate) batch_size: usize,
+    pub(crate) poll_interval: Duration,
+    /// How long to back off before retrying a batch the function failed.
+    pub(crate) visibility_timeout: Duration,
+    pub(crate) max_backoff: Duration,
+    /// How many times a batch is delivered and fails before it's given up on, mirroring
+    /// an SQS redrive policy's `maxReceiveCount`. Only takes effect when
+    /// `dead_letter` is configured: a batch that keeps failing with no dead
+    /// letter to fall back to just keeps retrying forever instead.
+    pub(crate) max_receive_count: usize,
+    /// Where to send a batch that's failed `max_receive_count` times,
+    /// instead of retrying it forever.
+    pub(crate) dead_letter: Option<Sender<Vec<serde_json::Value>>>,
+    /// Local stand-in for the queue/topic/stream this source reads from.
+    /// A test harness pushes records here; `next_batch` drains them for
+    /// every [`EventSourceKind`] except [`EventSourceKind::File`], which
+    /// reads directly off disk instead.
+    pub(crate) queue: SourceQueue,
+}
+
+/// The shape of envelope a source's records get assembled into before
+/// being submitted as an invocation.
+#[derive(Clone, Debug)]
+pub(crate) enum EventSourceKind {
+    Sqs { queue_url: String },
+    Sns { topic_arn: String },
+    Kinesis { stream_arn: String },
+    DynamoDbStream { stream_arn: String },
+    /// Records read from a single file, one JSON record per line,
+    /// advancing an in-memory line cursor once a batch succeeds or is
+    /// dead-lettered; a failed batch's lines are read and delivered again.
+    /// A directory is not supported.
+    File {
+        path: PathBuf,
+        cursor: Arc<tokio::sync::RwLock<usize>>,
+    },
+}
+
+/// Builds the Watchexec instance that supervises a single function's
+/// `cargo run` process, reloading it according to `watcher_config`'s
+/// [`OnBusyUpdate`] mode whenever a watched path changes. Except under
+/// [`OnBusyUpdate::DoNothing`] with the process still running, where the
+/// change is ignored outright and nothing is rebuilt, a `cargo build`
+/// that fails ahead of a reload is reported, via `req_cache`, to every
+/// invocation still queued for `instance_id`, instead of killing the
+/// instance that's still serving with its last good binary. The same
+/// preflight build also covers `instance_id`'s very
+/// first start, so a failure there is recorded with `req_cache` too —
+/// see [`RequestCache::mark_build_failed`] — keeping `instance_id` from
+/// being treated as a healthy, idle target for invocations that arrive
+/// after this one, since no process is ever running to serve them.
+pub(crate) async fn new(
+    cmd: Command,
+    watcher_config: WatcherConfig,
+    cargo_options: CargoOptions,
+    req_cache: RequestCache,
+    instance_id: usize,
+) -> Result<Arc<Watchexec>, ServerError> {
+    let on_busy_update = watcher_config.on_busy_update;
+    let stop_timeout = watcher_config.stop_timeout;
+    let manifest_path = watcher_config.manifest_path.clone();
+    let name = watcher_config.name.clone();
+    let bin_name = watcher_config.bin_name.clone();
+
+    let wx = Watchexec::new(move |mut action| {
+        if action.signals().next().is_some() {
+            action.outcome(Outcome::both(Outcome::Stop, Outcome::Exit));
+            return action;
+        }
+
+        let is_running = action.is_running();
+        let decision = reload_decision(on_busy_update, is_running);
+        let on_change = reload_outcome(decision, stop_timeout);
+
+        // `DoNothing` while the instance is still running ignores the
+        // change outright, per its own doc comment: nothing is being
+        // replaced, so there's no process for a failing rebuild to keep
+        // off, and reporting the failure to this instance's queued
+        // invocations would blame them for a reload that was never going to
+        // happen.
+        if decision == ReloadDecision::Skip {
+            action.outcome(on_change);
+            return action;
+        }
+
+        // Rebuild ahead of the reload, with the same flags the real run
+        // uses, so a failing build is caught before the process that
+        // would replace the running one is even spawned; on failure,
+        // keep the last good binary live and report the error to the
+        // invocations still queued for this instance instead of
+        // reloading into a dead process. `run_cargo_build` shells out and
+        // blocks for as long as the build takes, so it's run via
+        // `block_in_place` to avoid stalling this worker thread's other
+        // async work (including unrelated functions' invocation handling)
+        // for however long that is.
+        let build_result = tokio::task::block_in_place(|| {
+            run_cargo_build(manifest_path.as_deref(), &cargo_options, bin_name.as_deref())
+        });
+
+        match build_result {
+            Ok(()) => {
+                let req_cache = req_cache.clone();
+                let name = name.clone();
+                tokio::spawn(async move {
+                    req_cache.mark_build_succeeded(&name, instance_id).await;
+                });
+                action.outcome(on_change)
+            }
+            Err(err) => {
+                error!(function = ?name, error = %err, "build failed, keeping last good binary running");
+                let req_cache = req_cache.clone();
+                let name = name.clone();
+                tokio::spawn(async move {
+                    // Only an instance with no process running is marked
+                    // unhealthy; a running one keeps its last good binary.
+                    if !is_running {
+                        req_cache
+                            .mark_build_failed(&name, instance_id, err.clone())
+                            .await;
+                    }
+                    req_cache.fail_current(&name, instance_id, err).await;
+                });
+                action.outcome(Outcome::DoNothing);
+            }
+        }
+
+        action
+    })?;
+
+    wx.config.pathset(watcher_config.watch_paths.clone());
+    wx.config.command(cmd);
+
+    Ok(wx)
+}
+
+/// What a watched-path change should do to a function's process, given
+/// [`OnBusyUpdate`] and whether an instance is currently running. Kept
+/// separate from [`reload_outcome`]'s [`Outcome`] construction so the four
+/// modes, and the `DoNothing`-while-running carve-out, can be tested
+/// without watchexec's action/outcome plumbing.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ReloadDecision {
+    /// Nothing is running yet: start right away.
+    Start,
+    /// Stop the running process, then start the rebuilt one.
+    StopThenStart,
+    /// Wait for the running process to exit, then stop-and-start.
+    WaitThenReload,
+    /// Leave the running process alone: no reload, no rebuild.
+    Skip,
+    /// Send a graceful stop signal, give it `stop_timeout` to exit, then
+    /// stop-and-start.
+    SignalThenReload,
+}
+
+fn reload_decision(on_busy_update: OnBusyUpdate, is_running: bool) -> ReloadDecision {
+    match (on_busy_update, is_running) {
+        (OnBusyUpdate::Queue, true) => ReloadDecision::WaitThenReload,
+        (OnBusyUpdate::Queue, false) => ReloadDecision::Start,
+        (OnBusyUpdate::DoNothing, true) => ReloadDecision::Skip,
+        (OnBusyUpdate::DoNothing, false) => ReloadDecision::Start,
+        (OnBusyUpdate::Restart, true) => ReloadDecision::StopThenStart,
+        (OnBusyUpdate::Restart, false) => ReloadDecision::Start,
+        (OnBusyUpdate::Signal, true) => ReloadDecision::SignalThenReload,
+        (OnBusyUpdate::Signal, false) => ReloadDecision::Start,
+    }
+}
+
+/// Maps a [`ReloadDecision`] to the watchexec [`Outcome`] that applies it.
+fn reload_outcome(decision: ReloadDecision, stop_timeout: Duration) -> Outcome {
+    match decision {
+        ReloadDecision::Start => Outcome::Start,
+        ReloadDecision::StopThenStart => Outcome::both(Outcome::Stop, Outcome::Start),
+        ReloadDecision::WaitThenReload => {
+            Outcome::wait(Outcome::both(Outcome::Stop, Outcome::Start))
+        }
+        ReloadDecision::Skip => Outcome::DoNothing,
+        ReloadDecision::SignalThenReload => Outcome::both(
+            Outcome::Signal(Signal::Terminate),
+            Outcome::both(
+                Outcome::sleep(stop_timeout),
+                Outcome::both(Outcome::Stop, Outcome::Start),
+            ),
+        ),
+    }
+}
+
+/// Runs `cargo build` for the function, with the same `--features`,
+/// `--release`, and `--bin` flags the real `cargo run` uses, so a failing
+/// build can be caught and reported ahead of a reload instead of killing
+/// the currently running instance to replace it with a process that never
+/// comes up.
+fn run_cargo_build(
+    manifest_path: Option<&std::path::Path>,
+    cargo_options: &CargoOptions,
+    bin_name: Option<&str>,
+) -> Result<(), String> {
+    let mut cmd = StdCommand::new("cargo");
+    cmd.arg("build");
+    if let Some(manifest_path) = manifest_path {
+        cmd.arg("--manifest-path").arg(manifest_path);
+    }
+    if let Some(features) = cargo_options.features.as_deref() {
+        cmd.arg("--features").arg(features);
+    }
+    if cargo_options.release {
+        cmd.arg("--release");
+    }
+    if let Some(bin_name) = bin_name {
+        cmd.arg("--bin").arg(bin_name);
+    }
+
+    let output = cmd
+        .output()
+        .map_err(|err| format!("failed to run `cargo build`: {err}"))?;
+
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(String::from_utf8_lossy(&output.stderr).into_owned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn queue_waits_while_running_and_reloads_when_idle() {
+        assert_eq!(
+            reload_decision(OnBusyUpdate::Queue, true),
+            ReloadDecision::WaitThenReload
+        );
+        assert_eq!(
+            reload_decision(OnBusyUpdate::Queue, false),
+            ReloadDecision::Start
+        );
+    }
+
+    #[test]
+    fn do_nothing_skips_while_running_and_starts_when_idle() {
+        assert_eq!(
+            reload_decision(OnBusyUpdate::DoNothing, true),
+            ReloadDecision::Skip
+        );
+        assert_eq!(
+            reload_decision(OnBusyUpdate::DoNothing, false),
+            ReloadDecision::Start
+        );
+    }
+
+    #[test]
+    fn restart_always_reloads_immediately() {
+        assert_eq!(
+            reload_decision(OnBusyUpdate::Restart, true),
+            ReloadDecision::StopThenStart
+        );
+        assert_eq!(
+            reload_decision(OnBusyUpdate::Restart, false),
+            ReloadDecision::Start
+        );
+    }
+
+    #[test]
+    fn signal_sends_a_stop_signal_while_running_and_starts_when_idle() {
+        assert_eq!(
+            reload_decision(OnBusyUpdate::Signal, true),
+            ReloadDecision::SignalThenReload
+        );
+        assert_eq!(
+            reload_decision(OnBusyUpdate::Signal, false),
+            ReloadDecision::Start
+        );
+    }
+}